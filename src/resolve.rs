@@ -13,6 +13,7 @@ use serde_derive::Deserialize;
 use serde_derive::Serialize;
 use serde_json::to_string_pretty;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::convert::Into;
 use std::path::{Path, PathBuf};
 
@@ -32,14 +33,20 @@ pub struct CrateDerivation {
     pub source: ResolvedSource,
     pub dependencies: Vec<ResolvedDependency>,
     pub build_dependencies: Vec<ResolvedDependency>,
-    /// Feature rules. Which feature (key) enables which other features (values).
-    pub features: BTreeMap<String, Vec<String>>,
+    /// The dev-dependencies of this crate, resolved only for workspace members (see
+    /// `is_root_or_workspace_member`) since they are only needed to build this crate's own
+    /// tests, benches and examples.
+    pub dev_dependencies: Vec<ResolvedDependency>,
+    /// Feature rules. Which feature (key) enables which other features/dependencies (values).
+    pub features: BTreeMap<String, Vec<FeatureValue>>,
     /// The resolved features for this crate for a default build as returned by cargo.
     pub resolved_default_features: Vec<String>,
     /// The build target for the custom build script.
     pub build: Option<BuildTarget>,
     /// The build target for the library.
     pub lib: Option<BuildTarget>,
+    /// The `test`, `bench` and `example` build targets, resolved only for workspace members.
+    pub dev_build_targets: Vec<BuildTarget>,
     pub has_bin: bool,
     pub proc_macro: bool,
     // This derivation builds the root crate or a workspace member.
@@ -54,11 +61,36 @@ impl CrateDerivation {
     ) -> Result<CrateDerivation, Error> {
         let resolved_dependencies = ResolvedDependencies::new(metadata, package)?;
 
-        let build_dependencies =
-            resolved_dependencies.filtered_dependencies(|d| d.kind == DependencyKind::Build);
-        let dependencies = resolved_dependencies.filtered_dependencies(|d| {
-            d.kind == DependencyKind::Normal || d.kind == DependencyKind::Unknown
-        });
+        let is_root_or_workspace_member = metadata
+            .root
+            .iter()
+            .chain(metadata.workspace_members.iter())
+            .any(|pkg_id| *pkg_id == package.id);
+
+        let (features, dep_colon_referenced) = parse_features(&package.features);
+
+        let build_dependencies = mark_implicit_features(
+            resolved_dependencies.filtered_dependencies(|d| d.kind == DependencyKind::Build),
+            &dep_colon_referenced,
+        );
+        let dependencies = mark_implicit_features(
+            resolved_dependencies.filtered_dependencies(|d| {
+                d.kind == DependencyKind::Normal || d.kind == DependencyKind::Unknown
+            }),
+            &dep_colon_referenced,
+        );
+        // Dev-dependencies are only needed to build a crate's own tests/benches/examples, so
+        // only resolve them for workspace members to avoid bloating the closure of every
+        // transitive library crate with dependencies it never builds.
+        let dev_dependencies = if is_root_or_workspace_member {
+            mark_implicit_features(
+                resolved_dependencies
+                    .filtered_dependencies(|d| d.kind == DependencyKind::Development),
+                &dep_colon_referenced,
+            )
+        } else {
+            Vec::new()
+        };
 
         let package_path = package
             .manifest_path
@@ -79,6 +111,19 @@ impl CrateDerivation {
             .find(|t| t.kind.iter().any(|k| k == "custom-build"))
             .and_then(|target| BuildTarget::new(&target, &package_path).ok());
 
+        // Only needed alongside dev-dependencies to build this crate's own test/bench/example
+        // targets under Nix.
+        let dev_build_targets = if is_root_or_workspace_member {
+            package
+                .targets
+                .iter()
+                .filter(|t| t.kind.iter().any(|k| k == "test" || k == "bench" || k == "example"))
+                .filter_map(|target| BuildTarget::new(&target, &package_path).ok())
+                .collect()
+        } else {
+            Vec::new()
+        };
+
         let proc_macro = package
             .targets
             .iter()
@@ -89,12 +134,6 @@ impl CrateDerivation {
             .iter()
             .any(|t| t.kind.iter().any(|k| k == "bin"));
 
-        let is_root_or_workspace_member = metadata
-            .root
-            .iter()
-            .chain(metadata.workspace_members.iter())
-            .any(|pkg_id| *pkg_id == package.id);
-
         Ok(CrateDerivation {
             crate_name: package.name.clone(),
             edition: package.edition.clone(),
@@ -102,11 +141,7 @@ impl CrateDerivation {
             package_id: package.id.clone(),
             version: package.version.clone(),
             source: ResolvedSource::new(&config, &package, &package_path)?,
-            features: package
-                .features
-                .iter()
-                .map(|(name, feature_list)| (name.clone(), feature_list.clone()))
-                .collect(),
+            features,
             resolved_default_features: metadata
                 .nodes_by_id
                 .get(&package.id)
@@ -114,8 +149,10 @@ impl CrateDerivation {
                 .unwrap_or_else(|| Vec::new()),
             dependencies,
             build_dependencies,
+            dev_dependencies,
             build,
             lib,
+            dev_build_targets,
             proc_macro,
             has_bin,
             is_root_or_workspace_member,
@@ -123,6 +160,91 @@ impl CrateDerivation {
     }
 }
 
+/// Parse a package's raw `features` table into structured `FeatureValue`s, and collect the
+/// names of optional dependencies referenced via `dep:name` anywhere in it: Cargo suppresses
+/// such a dependency's implicit same-named feature.
+fn parse_features(
+    features: &BTreeMap<String, Vec<String>>,
+) -> (BTreeMap<String, Vec<FeatureValue>>, HashSet<String>) {
+    let mut parsed = BTreeMap::new();
+    let mut dep_colon_referenced = HashSet::new();
+
+    for (name, values) in features {
+        let parsed_values: Vec<FeatureValue> = values.iter().map(|v| FeatureValue::parse(v)).collect();
+        for value in &parsed_values {
+            if let FeatureValue::Dep(dep) = value {
+                dep_colon_referenced.insert(dep.clone());
+            }
+        }
+        parsed.insert(name.clone(), parsed_values);
+    }
+
+    (parsed, dep_colon_referenced)
+}
+
+/// Set `implicit_feature` on each optional dependency depending on whether it is referenced
+/// via `dep:name` anywhere in the package's `features` table.
+fn mark_implicit_features(
+    dependencies: Vec<ResolvedDependency>,
+    dep_colon_referenced: &HashSet<String>,
+) -> Vec<ResolvedDependency> {
+    dependencies
+        .into_iter()
+        .map(|mut dependency| {
+            if dependency.optional {
+                // `dep:`/`?/` syntax always refers to the dependency's name as it appears in
+                // the manifest's `[dependencies]` table, i.e. its rename if it has one, not
+                // the underlying package name.
+                let manifest_name = dependency.rename.as_deref().unwrap_or(&dependency.name);
+                dependency.implicit_feature = !dep_colon_referenced.contains(manifest_name);
+            }
+            dependency
+        })
+        .collect()
+}
+
+/// A single parsed entry in a feature's list of implied features/dependencies, as found on
+/// the right-hand side of a package's `features` table.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum FeatureValue {
+    /// A plain feature name, e.g. `"some-feature"`.
+    Feature(String),
+    /// `"dep:some-crate"`: activates the optional dependency `some-crate` without implying a
+    /// feature of the same name.
+    Dep(String),
+    /// `"some-crate/some-feature"`, or weak `"some-crate?/some-feature"` if `weak` is set:
+    /// activates `some-feature` on `some-crate`, additionally activating `some-crate` itself
+    /// unless weak.
+    DepFeature {
+        dep: String,
+        feature: String,
+        weak: bool,
+    },
+}
+
+impl FeatureValue {
+    fn parse(value: &str) -> FeatureValue {
+        if let Some(dep) = value.strip_prefix("dep:") {
+            return FeatureValue::Dep(dep.to_string());
+        }
+        if let Some((dep, feature)) = value.split_once("?/") {
+            return FeatureValue::DepFeature {
+                dep: dep.to_string(),
+                feature: feature.to_string(),
+                weak: true,
+            };
+        }
+        if let Some((dep, feature)) = value.split_once('/') {
+            return FeatureValue::DepFeature {
+                dep: dep.to_string(),
+                feature: feature.to_string(),
+                weak: false,
+            };
+        }
+        FeatureValue::Feature(value.to_string())
+    }
+}
+
 /// A build target of a crate.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct BuildTarget {
@@ -141,6 +263,18 @@ impl BuildTarget {
     }
 }
 
+/// The human-facing Cargo git reference a `Git` source was pinned from. `rev` is always
+/// recorded separately for reproducibility; this just tells readers which kind of reference
+/// produced it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum GitReference {
+    Branch(String),
+    Tag(String),
+    Rev(String),
+    /// No `branch=`, `tag=` or `rev=` was specified; pinned to the default branch's HEAD.
+    DefaultBranch,
+}
+
 /// Specifies how to retrieve the source code.
 #[derive(Debug, Deserialize, Serialize)]
 pub enum ResolvedSource {
@@ -150,15 +284,69 @@ pub enum ResolvedSource {
     Git {
         #[serde(with = "url_serde")]
         url: Url,
+        /// The commit this dependency is pinned to, for reproducibility.
         rev: String,
-        r#ref: Option<String>
+        /// The human-facing reference (branch/tag/rev) that resolved to `rev`.
+        r#ref: GitReference,
     },
     LocalDirectory {
         path: PathBuf,
     },
+    Registry {
+        #[serde(with = "url_serde")]
+        index_url: Url,
+        sha256: Option<String>,
+    },
 }
 
 const GIT_SOURCE_PREFIX: &str = "git+";
+const REGISTRY_SOURCE_PREFIX: &str = "registry+";
+const SPARSE_REGISTRY_SOURCE_PREFIX: &str = "sparse+";
+
+/// The registry API's default `dl` template, used when a registry's `config.json` does not
+/// specify a custom one. See <https://doc.rust-lang.org/cargo/reference/registries.html#index-format>.
+const DEFAULT_DL_TEMPLATE: &str = "{index}/api/v1/crates/{crate}/{version}/download";
+
+/// Resolve the download URL for a crate's `.crate` tarball from a registry's `config.json`
+/// `dl` field, substituting the `{crate}`, `{version}`, `{prefix}` and `{lowerprefix}`
+/// placeholders Cargo itself recognizes. Falls back to the default crates.io-style API
+/// template when `dl_template` is `None`, matching Cargo's own behavior.
+///
+/// This only handles the URL templating, which is pure and needs no network access. Actually
+/// resolving a `ResolvedSource::Registry`'s `sha256` still requires fetching this URL's
+/// `.crate` tarball and hashing it, which belongs in the same out-of-tree prefetch pass that
+/// fills in `ResolvedSource::CratesIo`'s hash (`prefetch_and_fill_crates_sha256`); that pass
+/// does not yet call this function.
+pub fn registry_download_url(
+    index_url: &Url,
+    dl_template: Option<&str>,
+    crate_name: &str,
+    version: &Version,
+) -> Result<Url, Error> {
+    let prefix = registry_name_prefix(crate_name);
+    let template = dl_template.unwrap_or(DEFAULT_DL_TEMPLATE);
+    let expanded = template
+        .replace("{index}", index_url.as_str().trim_end_matches('/'))
+        .replace("{crate}", crate_name)
+        .replace("{version}", &version.to_string())
+        .replace("{prefix}", &prefix)
+        .replace("{lowerprefix}", &prefix.to_lowercase());
+    Url::parse(&expanded)
+        .map_err(|e| format_err!("Invalid registry download URL '{}': {}", expanded, e))
+}
+
+/// The directory-sharding prefix Cargo uses for a crate name, both in a registry index's
+/// file layout and in the `{prefix}`/`{lowerprefix}` dl-template placeholders: 1- and 2-letter
+/// names get their own single-character directory, 3-letter names are split `3/{first
+/// letter}`, and everything else is split into its first two and next two characters.
+fn registry_name_prefix(crate_name: &str) -> String {
+    match crate_name.len() {
+        1 => "1".to_string(),
+        2 => "2".to_string(),
+        3 => format!("3/{}", &crate_name[..1]),
+        _ => format!("{}/{}", &crate_name[..2], &crate_name[2..4]),
+    }
+}
 
 impl ResolvedSource {
     pub fn new(
@@ -187,6 +375,30 @@ impl ResolvedSource {
         source: &Source,
     ) -> Result<ResolvedSource, Error> {
         let source_string = source.to_string();
+        if source_string.starts_with(REGISTRY_SOURCE_PREFIX) {
+            let index_url =
+                url::Url::parse(&source_string[REGISTRY_SOURCE_PREFIX.len()..])?;
+            return Ok(ResolvedSource::Registry {
+                index_url,
+                // TODO(follow-up, not yet implemented): fetch this registry's `config.json`
+                // for its `dl` template, pass it to `registry_download_url` above together
+                // with the crate name/version to get the tarball URL, then download and hash
+                // it. That network step belongs in the same out-of-tree prefetch pass that
+                // fills in `ResolvedSource::CratesIo::sha256` (`prefetch_and_fill_crates_sha256`),
+                // which does not yet have registry support wired in.
+                sha256: None,
+            });
+        }
+        if source_string.starts_with(SPARSE_REGISTRY_SOURCE_PREFIX) {
+            let index_url = url::Url::parse(&source_string)?;
+            return Ok(ResolvedSource::Registry {
+                index_url,
+                // TODO(follow-up, not yet implemented): see the registry+ branch above;
+                // sparse registries additionally require the `config.json` fetch to go over
+                // HTTP(S) directly rather than through a cloned index checkout.
+                sha256: None,
+            });
+        }
         if !source_string.starts_with(GIT_SOURCE_PREFIX) {
             return ResolvedSource::fallback_to_local_directory(
                 config,
@@ -195,29 +407,15 @@ impl ResolvedSource {
                 "No 'git+' prefix found.",
             );
         }
-        let mut url = url::Url::parse(&source_string[GIT_SOURCE_PREFIX.len()..])?;
-        let mut query_pairs = url.query_pairs();
-
-        let branch = query_pairs.find(|(k, _)| k == "branch").map(|(_, v)| v.to_string());
-        let rev = if let Some((_, rev)) = query_pairs.find(|(k, _)| k == "rev") {
-            rev.to_string()
-        } else if let Some(rev) = url.fragment() {
-            rev.to_string()
-        } else {
-            return ResolvedSource::fallback_to_local_directory(
+        match parse_git_source_url(&source_string[GIT_SOURCE_PREFIX.len()..]) {
+            Ok((url, rev, r#ref)) => Ok(ResolvedSource::Git { url, rev, r#ref }),
+            Err(_) => ResolvedSource::fallback_to_local_directory(
                 config,
                 package,
                 package_path,
                 "No git revision found.",
-            );
-        };
-        url.set_query(None);
-        url.set_fragment(None);
-        Ok(ResolvedSource::Git {
-            url,
-            rev,
-            r#ref: branch,
-        })
+            ),
+        }
     }
 
     fn fallback_to_local_directory(
@@ -288,6 +486,38 @@ impl ResolvedSource {
     }
 }
 
+/// Parse the part of a `git+` source string after the `git+` prefix into the repository URL,
+/// the commit it is pinned to (for reproducibility), and the human-facing `GitReference`
+/// (branch/tag/rev/default-branch) that produced that pin. Cargo only ever encodes one of
+/// `branch`, `tag` or `rev` as a query parameter; if none is present the dependency is pinned
+/// to the default branch's HEAD, identified only by the commit in the URL fragment.
+fn parse_git_source_url(url_str: &str) -> Result<(Url, String, GitReference), Error> {
+    let mut url = url::Url::parse(url_str)?;
+
+    let branch = url.query_pairs().find(|(k, _)| k == "branch").map(|(_, v)| v.to_string());
+    let tag = url.query_pairs().find(|(k, _)| k == "tag").map(|(_, v)| v.to_string());
+    let explicit_rev = url.query_pairs().find(|(k, _)| k == "rev").map(|(_, v)| v.to_string());
+
+    let rev = explicit_rev
+        .clone()
+        .or_else(|| url.fragment().map(|f| f.to_string()))
+        .ok_or_else(|| format_err!("No git revision found."))?;
+
+    let git_ref = if let Some(branch) = branch {
+        GitReference::Branch(branch)
+    } else if let Some(tag) = tag {
+        GitReference::Tag(tag)
+    } else if let Some(rev) = explicit_rev {
+        GitReference::Rev(rev)
+    } else {
+        GitReference::DefaultBranch
+    };
+
+    url.set_query(None);
+    url.set_fragment(None);
+    Ok((url, rev, git_ref))
+}
+
 /// The resolved dependencies of one package/crate.
 struct ResolvedDependencies<'a> {
     /// The corresponding packages for the dependencies.
@@ -361,14 +591,26 @@ impl<'a> ResolvedDependencies<'a> {
                         let dependency = ds[0];
                         let targets = ds.iter()
                                 .filter(|d| d.target.is_some())
-                                .map(|d| d.target.as_ref().unwrap().to_string())
-                                .collect::<Vec<String>>();
+                                .map(|d| {
+                                    let raw = d.target.as_ref().unwrap().to_string();
+                                    TargetPredicate::parse(&raw).unwrap_or_else(|e| {
+                                        eprintln!(
+                                            "WARNING: Could not parse target expression '{}': {}. Treating it as a bare target triple.",
+                                            raw, e
+                                        );
+                                        TargetPredicate::Triple(raw)
+                                    })
+                                })
+                                .collect::<Vec<TargetPredicate>>();
                         ResolvedDependency {
                             name: dependency.name.clone(),
                             rename: dependency.rename.clone(),
                             package_id: d.id.clone(),
                             targets,
                             optional: dependency.optional,
+                            // Corrected afterwards by `mark_implicit_features` once the
+                            // package's full `features` table has been parsed.
+                            implicit_feature: true,
                             uses_default_features: dependency.uses_default_features,
                             features: dependency.features.clone(),
                         }
@@ -384,13 +626,413 @@ pub struct ResolvedDependency {
     /// New name for the dependency if it is renamed.
     pub rename: Option<String>,
     pub package_id: PackageId,
-    /// The cfg expressions for conditionally enabling the dependency (if any).
-    /// Can also be a target "triplet".
-    pub targets: Vec<String>,
+    /// The parsed target predicates for conditionally enabling the dependency (if any):
+    /// either a bare target triple or a `cfg(...)` expression.
+    pub targets: Vec<TargetPredicate>,
     /// Whether this dependency is optional and thus needs to be enabled via a feature.
     pub optional: bool,
+    /// Whether this (optional) dependency still gets an implicit feature of the same name.
+    /// Cargo suppresses that implicit feature if the dependency is referenced via `dep:name`
+    /// anywhere in the package's `features` table. Meaningless when `optional` is `false`.
+    pub implicit_feature: bool,
     /// Whether the crate uses this dependency with default features enabled.
     pub uses_default_features: bool,
     /// Extra-enabled features.
     pub features: Vec<String>,
 }
+
+/// A parsed `target` entry of a `[dependencies]` table entry: either a bare target triple
+/// or a `cfg(...)` expression.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TargetPredicate {
+    /// Matches a single target platform exactly, e.g. `x86_64-unknown-linux-gnu`.
+    Triple(String),
+    /// Matches platforms satisfying a `cfg(...)` expression.
+    Cfg(CfgExpr),
+}
+
+impl TargetPredicate {
+    /// Parse a dependency's raw `target` string, e.g. `cfg(unix)` or
+    /// `x86_64-pc-windows-gnu`.
+    pub fn parse(target: &str) -> Result<TargetPredicate, Error> {
+        let target = target.trim();
+        match target.strip_prefix("cfg(").and_then(|s| s.strip_suffix(')')) {
+            Some(inner) => Ok(TargetPredicate::Cfg(CfgExpr::parse(inner)?)),
+            None => Ok(TargetPredicate::Triple(target.to_string())),
+        }
+    }
+
+    /// Whether this predicate is satisfied by the given platform, described by its `rustc`
+    /// target triple and a map of `cfg` keys to values (e.g. `target_os` -> `linux`); flags
+    /// without a value (e.g. `unix`) are present in the map with an empty string value.
+    pub fn matches(&self, rustc_triple: &str, cfg: &HashMap<&str, &str>) -> bool {
+        match self {
+            TargetPredicate::Triple(triple) => triple == rustc_triple,
+            TargetPredicate::Cfg(expr) => expr.eval(cfg),
+        }
+    }
+}
+
+/// The recursive grammar of a Cargo `cfg(...)` target expression.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum CfgExpr {
+    All(Vec<CfgExpr>),
+    Any(Vec<CfgExpr>),
+    Not(Box<CfgExpr>),
+    /// `key = "value"`.
+    Equals(String, String),
+    /// A bare key, e.g. `unix` or `windows`.
+    Flag(String),
+}
+
+impl CfgExpr {
+    fn parse(input: &str) -> Result<CfgExpr, Error> {
+        let mut parser = CfgParser { rest: input };
+        let expr = parser.parse_expr()?;
+        parser.skip_whitespace();
+        if !parser.rest.is_empty() {
+            return Err(format_err!(
+                "Unexpected trailing input in cfg() expression: '{}'",
+                parser.rest
+            ));
+        }
+        Ok(expr)
+    }
+
+    fn eval(&self, cfg: &HashMap<&str, &str>) -> bool {
+        match self {
+            // `all()` of an empty list is vacuously true.
+            CfgExpr::All(exprs) => exprs.iter().all(|e| e.eval(cfg)),
+            // `any()` of an empty list is vacuously false.
+            CfgExpr::Any(exprs) => exprs.iter().any(|e| e.eval(cfg)),
+            CfgExpr::Not(expr) => !expr.eval(cfg),
+            CfgExpr::Equals(key, value) => cfg.get(key.as_str()) == Some(&value.as_str()),
+            CfgExpr::Flag(key) => cfg.contains_key(key.as_str()),
+        }
+    }
+}
+
+/// Minimal hand-rolled recursive-descent parser for the `cfg(...)` grammar.
+struct CfgParser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> CfgParser<'a> {
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        self.skip_whitespace();
+        if let Some(rest) = self.rest.strip_prefix(c) {
+            self.rest = rest;
+            Ok(())
+        } else {
+            Err(format_err!(
+                "Expected '{}' in cfg() expression, found '{}'",
+                c,
+                self.rest
+            ))
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<CfgExpr, Error> {
+        self.skip_whitespace();
+        if let Some(rest) = self.rest.strip_prefix("all(") {
+            self.rest = rest;
+            return Ok(CfgExpr::All(self.parse_list()?));
+        }
+        if let Some(rest) = self.rest.strip_prefix("any(") {
+            self.rest = rest;
+            return Ok(CfgExpr::Any(self.parse_list()?));
+        }
+        if let Some(rest) = self.rest.strip_prefix("not(") {
+            self.rest = rest;
+            let inner = self.parse_expr()?;
+            self.expect(')')?;
+            return Ok(CfgExpr::Not(Box::new(inner)));
+        }
+        self.parse_key_value()
+    }
+
+    fn parse_list(&mut self) -> Result<Vec<CfgExpr>, Error> {
+        let mut exprs = Vec::new();
+        self.skip_whitespace();
+        if self.rest.starts_with(')') {
+            self.rest = &self.rest[1..];
+            return Ok(exprs);
+        }
+        loop {
+            exprs.push(self.parse_expr()?);
+            self.skip_whitespace();
+            if let Some(rest) = self.rest.strip_prefix(',') {
+                self.rest = rest;
+                self.skip_whitespace();
+                if self.rest.starts_with(')') {
+                    self.rest = &self.rest[1..];
+                    break;
+                }
+                continue;
+            }
+            self.expect(')')?;
+            break;
+        }
+        Ok(exprs)
+    }
+
+    fn parse_key_value(&mut self) -> Result<CfgExpr, Error> {
+        self.skip_whitespace();
+        let key_end = self
+            .rest
+            .find(|c: char| c == '=' || c == ',' || c == ')' || c.is_whitespace())
+            .unwrap_or(self.rest.len());
+        if key_end == 0 {
+            return Err(format_err!(
+                "Expected identifier in cfg() expression, found '{}'",
+                self.rest
+            ));
+        }
+        let key = self.rest[..key_end].to_string();
+        self.rest = &self.rest[key_end..];
+        self.skip_whitespace();
+        if let Some(rest) = self.rest.strip_prefix('=') {
+            self.rest = rest;
+            self.skip_whitespace();
+            let rest = self
+                .rest
+                .strip_prefix('"')
+                .ok_or_else(|| format_err!("Expected quoted string after '=' in cfg() expression"))?;
+            let value_end = rest
+                .find('"')
+                .ok_or_else(|| format_err!("Unterminated string in cfg() expression"))?;
+            let value = rest[..value_end].to_string();
+            self.rest = &rest[value_end + 1..];
+            Ok(CfgExpr::Equals(key, value))
+        } else {
+            Ok(CfgExpr::Flag(key))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_predicate_parses_bare_triple() {
+        assert_eq!(
+            TargetPredicate::parse("x86_64-unknown-linux-gnu").unwrap(),
+            TargetPredicate::Triple("x86_64-unknown-linux-gnu".to_string())
+        );
+    }
+
+    #[test]
+    fn target_predicate_parses_flag_and_key_value() {
+        assert_eq!(
+            TargetPredicate::parse("cfg(unix)").unwrap(),
+            TargetPredicate::Cfg(CfgExpr::Flag("unix".to_string()))
+        );
+        assert_eq!(
+            TargetPredicate::parse("cfg(target_os = \"macos\")").unwrap(),
+            TargetPredicate::Cfg(CfgExpr::Equals(
+                "target_os".to_string(),
+                "macos".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn target_predicate_parses_nested_all_any_not() {
+        let parsed = TargetPredicate::parse(
+            "cfg(all(not(windows), any(target_arch = \"x86\", target_arch = \"x86_64\")))",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed,
+            TargetPredicate::Cfg(CfgExpr::All(vec![
+                CfgExpr::Not(Box::new(CfgExpr::Flag("windows".to_string()))),
+                CfgExpr::Any(vec![
+                    CfgExpr::Equals("target_arch".to_string(), "x86".to_string()),
+                    CfgExpr::Equals("target_arch".to_string(), "x86_64".to_string()),
+                ]),
+            ]))
+        );
+    }
+
+    #[test]
+    fn cfg_expr_empty_all_is_true_empty_any_is_false() {
+        let cfg = HashMap::new();
+        assert!(CfgExpr::All(Vec::new()).eval(&cfg));
+        assert!(!CfgExpr::Any(Vec::new()).eval(&cfg));
+    }
+
+    #[test]
+    fn cfg_expr_evaluates_against_platform_cfg_map() {
+        let mut cfg = HashMap::new();
+        cfg.insert("target_os", "linux");
+        cfg.insert("unix", "");
+
+        let matches = TargetPredicate::parse("cfg(all(unix, target_os = \"linux\"))")
+            .unwrap()
+            .matches("x86_64-unknown-linux-gnu", &cfg);
+        assert!(matches);
+
+        let no_match = TargetPredicate::parse("cfg(windows)")
+            .unwrap()
+            .matches("x86_64-unknown-linux-gnu", &cfg);
+        assert!(!no_match);
+    }
+
+    #[test]
+    fn feature_value_parses_dep_colon_dep_feature_and_weak_dep_feature() {
+        assert_eq!(
+            FeatureValue::parse("some-feature"),
+            FeatureValue::Feature("some-feature".to_string())
+        );
+        assert_eq!(
+            FeatureValue::parse("dep:some-crate"),
+            FeatureValue::Dep("some-crate".to_string())
+        );
+        assert_eq!(
+            FeatureValue::parse("some-crate/some-feature"),
+            FeatureValue::DepFeature {
+                dep: "some-crate".to_string(),
+                feature: "some-feature".to_string(),
+                weak: false,
+            }
+        );
+        assert_eq!(
+            FeatureValue::parse("some-crate?/some-feature"),
+            FeatureValue::DepFeature {
+                dep: "some-crate".to_string(),
+                feature: "some-feature".to_string(),
+                weak: true,
+            }
+        );
+    }
+
+    #[test]
+    fn mark_implicit_features_does_not_suppress_dep_without_dep_colon_reference() {
+        let deps = vec![resolved_dependency("libc", None, true)];
+        let mut dep_colon_referenced = HashSet::new();
+        dep_colon_referenced.insert("unrelated".to_string());
+
+        let marked = mark_implicit_features(deps, &dep_colon_referenced);
+        assert!(marked[0].implicit_feature);
+    }
+
+    #[test]
+    fn mark_implicit_features_suppresses_implicit_feature_for_renamed_dep_colon_reference() {
+        // `myalias = { package = "libc", optional = true }` plus `foo = ["dep:myalias"]`:
+        // cargo_metadata's `Dependency::name` is the upstream package name ("libc"), while
+        // `dep:` syntax refers to the manifest-visible name, i.e. the rename ("myalias").
+        let deps = vec![resolved_dependency("libc", Some("myalias"), true)];
+        let mut dep_colon_referenced = HashSet::new();
+        dep_colon_referenced.insert("myalias".to_string());
+
+        let marked = mark_implicit_features(deps, &dep_colon_referenced);
+        assert!(!marked[0].implicit_feature);
+    }
+
+    #[test]
+    fn mark_implicit_features_ignores_non_optional_dependencies() {
+        let deps = vec![resolved_dependency("libc", Some("myalias"), false)];
+        let mut dep_colon_referenced = HashSet::new();
+        dep_colon_referenced.insert("myalias".to_string());
+
+        let marked = mark_implicit_features(deps, &dep_colon_referenced);
+        assert!(marked[0].implicit_feature);
+    }
+
+    fn resolved_dependency(name: &str, rename: Option<&str>, optional: bool) -> ResolvedDependency {
+        ResolvedDependency {
+            name: name.to_string(),
+            rename: rename.map(str::to_string),
+            package_id: PackageId {
+                repr: format!("{} 1.0.0 (registry+https://github.com/rust-lang/crates.io-index)", name),
+            },
+            targets: Vec::new(),
+            optional,
+            implicit_feature: true,
+            uses_default_features: true,
+            features: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn git_source_url_prefers_branch_over_tag_and_rev() {
+        let (_, rev, git_ref) = parse_git_source_url(
+            "https://github.com/owner/repo?branch=main&tag=v1.2.3&rev=deadbeef#cafef00d",
+        )
+        .unwrap();
+        assert_eq!(git_ref, GitReference::Branch("main".to_string()));
+        // An explicit `rev=` always wins over the fragment as the commit pin, regardless of
+        // which reference kind (branch/tag) resolved `git_ref`.
+        assert_eq!(rev, "deadbeef");
+    }
+
+    #[test]
+    fn git_source_url_prefers_tag_over_rev_when_no_branch() {
+        let (_, _, git_ref) =
+            parse_git_source_url("https://github.com/owner/repo?tag=v1.2.3&rev=deadbeef#cafef00d")
+                .unwrap();
+        assert_eq!(git_ref, GitReference::Tag("v1.2.3".to_string()));
+    }
+
+    #[test]
+    fn git_source_url_falls_back_to_rev_query_param() {
+        let (_, rev, git_ref) =
+            parse_git_source_url("https://github.com/owner/repo?rev=deadbeef").unwrap();
+        assert_eq!(git_ref, GitReference::Rev("deadbeef".to_string()));
+        assert_eq!(rev, "deadbeef");
+    }
+
+    #[test]
+    fn git_source_url_with_only_a_fragment_is_default_branch() {
+        let (_, rev, git_ref) =
+            parse_git_source_url("https://github.com/owner/repo#cafef00d").unwrap();
+        assert_eq!(git_ref, GitReference::DefaultBranch);
+        assert_eq!(rev, "cafef00d");
+    }
+
+    #[test]
+    fn git_source_url_without_any_revision_is_an_error() {
+        assert!(parse_git_source_url("https://github.com/owner/repo").is_err());
+    }
+
+    #[test]
+    fn registry_name_prefix_matches_cargo_sharding() {
+        assert_eq!(registry_name_prefix("a"), "1");
+        assert_eq!(registry_name_prefix("ab"), "2");
+        assert_eq!(registry_name_prefix("abc"), "3/a");
+        assert_eq!(registry_name_prefix("serde"), "se/rd");
+    }
+
+    #[test]
+    fn registry_download_url_uses_default_template_when_none_given() {
+        let index_url = Url::parse("https://my-registry.example.com/index").unwrap();
+        let version = Version::parse("1.2.3").unwrap();
+        let url = registry_download_url(&index_url, None, "serde", &version).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://my-registry.example.com/index/api/v1/crates/serde/1.2.3/download"
+        );
+    }
+
+    #[test]
+    fn registry_download_url_substitutes_custom_dl_template() {
+        let index_url = Url::parse("https://my-registry.example.com/index").unwrap();
+        let version = Version::parse("1.2.3").unwrap();
+        let url = registry_download_url(
+            &index_url,
+            Some("{index}/{prefix}/{crate}/{crate}-{version}.crate"),
+            "serde",
+            &version,
+        )
+        .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://my-registry.example.com/index/se/rd/serde/serde-1.2.3.crate"
+        );
+    }
+}